@@ -0,0 +1,48 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use crate::mqtt_session_state::TransformRule;
+use serde::{Deserialize, Serialize};
+use zenoh::prelude::OwnedKeyExpr;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    pub port: String,
+    pub scope: Option<OwnedKeyExpr>,
+    #[serde(default)]
+    pub allow: Option<String>,
+    #[serde(default)]
+    pub deny: Option<String>,
+    /// Per-topic-pattern payload transformation rules applied by
+    /// `route_mqtt_to_zenoh`/`route_zenoh_to_mqtt` on both the v3 and v5
+    /// paths; see `mqtt_session_state::TransformRule`.
+    #[serde(default)]
+    pub transforms: Vec<TransformRule>,
+    /// Topic patterns served as Zenoh queryables instead of plain
+    /// subscriptions, matched by `mqtt_session_state_v5::is_rpc_topic`.
+    #[serde(default)]
+    pub rpc_topics: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: "1883".into(),
+            scope: None,
+            allow: None,
+            deny: None,
+            transforms: Vec::new(),
+            rpc_topics: Vec::new(),
+        }
+    }
+}
@@ -17,19 +17,71 @@ use async_channel::{Receiver, Sender};
 use async_std::sync::RwLock;
 use lazy_static::__Deref;
 use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::types::QoS;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 use zenoh::plugins::ZResult;
 use zenoh::prelude::r#async::*;
 use zenoh::subscriber::Subscriber;
 
+lazy_static::lazy_static! {
+    // Plugin-local retained-message store, keyed by Zenoh key expression.
+    // Zenoh itself has no notion of MQTT's retain flag, so a retained PUBLISH
+    // is both `put` on the session (for live subscribers) and kept here, so
+    // a client subscribing later can still be served the last known value.
+    pub(crate) static ref RETAINED: RwLock<HashMap<String, (Vec<u8>, Encoding)>> = RwLock::new(HashMap::new());
+}
+
+// how long a QoS 1/2 publish waits for the client's ack before the session
+// is considered wedged and gets closed
+const ACK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The payload representation on one side of a [`TransformRule`]. `Raw`
+/// leaves bytes untouched; `CanonicalJson` parses the payload as JSON and
+/// re-serializes it in a canonical (key-sorted) form, so heterogeneous
+/// device encodings of the same document compare and store identically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PayloadCodec {
+    Raw,
+    CanonicalJson,
+}
+
+/// A configured payload transformation for topics matching `pattern`
+/// (MQTT-style glob, matched the same way as the `allow`/`deny` lists).
+/// Rules are expected to live in `Config::transforms`; `route_mqtt_to_zenoh`
+/// and `route_zenoh_to_mqtt` consult the first matching rule and fall back
+/// to a verbatim passthrough when none matches.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub(crate) struct TransformRule {
+    pub(crate) pattern: String,
+    pub(crate) mqtt_codec: PayloadCodec,
+    pub(crate) zenoh_codec: PayloadCodec,
+}
+
+// the Last Will and Testament captured at CONNECT time, published once by
+// `publish_will()` or the teardown path in `spawn_mqtt_publisher` - whichever
+// notices the ungraceful disconnect first
+#[derive(Debug, Clone)]
+pub(crate) struct WillMessage {
+    pub(crate) topic: String,
+    pub(crate) payload: Bytes,
+    pub(crate) qos: QoS,
+    pub(crate) retain: bool,
+}
+
 #[derive(Debug)]
 pub(crate) struct MqttSessionState<'a> {
     pub(crate) client_id: String,
     pub(crate) zsession: Arc<Session>,
     pub(crate) config: Arc<Config>,
     pub(crate) subs: RwLock<HashMap<String, Subscriber<'a, ()>>>,
-    pub(crate) tx: Sender<(ByteString, Bytes)>,
+    pub(crate) tx: Sender<(ByteString, Bytes, QoS, bool)>,
+    pub(crate) will: Option<WillMessage>,
+    clean_disconnect: Arc<AtomicBool>,
+    will_published: Arc<AtomicBool>,
 }
 
 impl MqttSessionState<'_> {
@@ -38,9 +90,21 @@ impl MqttSessionState<'_> {
         zsession: Arc<Session>,
         config: Arc<Config>,
         sink: MqttSink,
+        will: Option<WillMessage>,
     ) -> MqttSessionState<'a> {
-        let (tx, rx) = async_channel::bounded::<(ByteString, Bytes)>(1);
-        spawn_mqtt_publisher(client_id.clone(), rx, sink);
+        let (tx, rx) = async_channel::bounded::<(ByteString, Bytes, QoS, bool)>(1);
+        let clean_disconnect = Arc::new(AtomicBool::new(false));
+        let will_published = Arc::new(AtomicBool::new(false));
+        spawn_mqtt_publisher(
+            client_id.clone(),
+            rx,
+            sink,
+            zsession.clone(),
+            config.clone(),
+            will.clone(),
+            clean_disconnect.clone(),
+            will_published.clone(),
+        );
 
         MqttSessionState {
             client_id,
@@ -48,10 +112,33 @@ impl MqttSessionState<'_> {
             config,
             subs: RwLock::new(HashMap::new()),
             tx,
+            will,
+            clean_disconnect,
+            will_published,
+        }
+    }
+
+    /// Marks this session as having ended with a clean DISCONNECT, so the
+    /// teardown path does not publish the client's Will message.
+    pub(crate) fn mark_clean_disconnect(&self) {
+        self.clean_disconnect.store(true, Ordering::Relaxed);
+    }
+
+    // publishes this session's Will message to Zenoh, if any and if it
+    // hasn't been published already - shares a claim flag with the
+    // automatic teardown path in `spawn_mqtt_publisher` so the Will is
+    // never sent twice regardless of which path notices the disconnect
+    // first
+    pub(crate) async fn publish_will(&self) -> ZResult<()> {
+        match &self.will {
+            Some(will) if claim_will(&self.will_published) => {
+                publish_will_message(&self.zsession, &self.config, &self.client_id, will).await
+            }
+            _ => Ok(()),
         }
     }
 
-    pub(crate) async fn map_mqtt_subscription<'a>(&'a self, topic: &str) -> ZResult<()> {
+    pub(crate) async fn map_mqtt_subscription<'a>(&'a self, topic: &str, qos: QoS) -> ZResult<()> {
         let sub_origin = if is_allowed(topic, &self.config) {
             // if topic is allowed, subscribe to publications coming from anywhere
             Locality::Any
@@ -73,9 +160,10 @@ impl MqttSessionState<'_> {
             let tx = self.tx.clone();
             let sub = self
                 .zsession
-                .declare_subscriber(ke)
+                .declare_subscriber(ke.clone())
                 .callback(move |sample| {
-                    if let Err(e) = route_zenoh_to_mqtt(sample, &client_id, &config, &tx) {
+                    if let Err(e) = route_zenoh_to_mqtt(sample, &client_id, &config, &tx, qos, false)
+                    {
                         log::warn!("{}", e);
                     }
                 })
@@ -83,6 +171,8 @@ impl MqttSessionState<'_> {
                 .res()
                 .await?;
             subs.insert(topic.into(), sub);
+
+            self.send_retained(&ke, topic, qos).await;
             Ok(())
         } else {
             log::debug!(
@@ -94,10 +184,82 @@ impl MqttSessionState<'_> {
         }
     }
 
+    // serves any retained message matching `ke` to a client that just subscribed
+    // to `topic`: a Zenoh query is tried first, since it also reaches retained
+    // values held by other nodes (e.g. a Zenoh storage); the local store is only
+    // consulted as a fallback when the query comes back empty, so a client never
+    // gets the same retained value delivered twice
+    async fn send_retained(&self, ke: &KeyExpr<'_>, topic: &str, qos: QoS) {
+        let mut served_by_query = false;
+
+        match self.zsession.get(ke).res().await {
+            Ok(replies) => {
+                while let Ok(reply) = replies.recv_async().await {
+                    match reply.sample {
+                        Ok(sample) => {
+                            served_by_query = true;
+                            if let Err(e) = route_zenoh_to_mqtt_async(
+                                sample,
+                                &self.client_id,
+                                &self.config,
+                                &self.tx,
+                                qos,
+                                true,
+                            )
+                            .await
+                            {
+                                log::warn!("{}", e);
+                            }
+                        }
+                        Err(e) => log::debug!(
+                            "MQTT client {}: error fetching retained value for '{}': {}",
+                            self.client_id,
+                            ke,
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) => log::debug!(
+                "MQTT client {}: failed to query retained value for '{}': {}",
+                self.client_id,
+                ke,
+                e
+            ),
+        }
+
+        if served_by_query {
+            return;
+        }
+
+        if let Some((payload, encoding)) = RETAINED.read().await.get(ke.as_str()) {
+            log::trace!(
+                "MQTT client {}: serving locally-retained value for '{}' to newly subscribed '{}'",
+                self.client_id,
+                ke,
+                topic
+            );
+            let _ = encoding; // the retained payload is forwarded as-is; encoding is for Zenoh-side consumers
+            if let Err(e) = self
+                .tx
+                .send((topic.into(), payload.clone().into(), qos, true))
+                .await
+            {
+                log::warn!(
+                    "MQTT client {}: error re-publishing retained value for '{}': {}",
+                    self.client_id,
+                    topic,
+                    e
+                );
+            }
+        }
+    }
+
     pub(crate) async fn route_mqtt_to_zenoh(
         &self,
         mqtt_topic: &ntex::router::Path<ByteString>,
         payload: &Bytes,
+        retain: bool,
     ) -> ZResult<()> {
         let topic = mqtt_topic.get_ref().as_str();
         let destination = if is_allowed(topic, &self.config) {
@@ -118,17 +280,32 @@ impl MqttSessionState<'_> {
         } else {
             topic.try_into()?
         };
-        let encoding = guess_encoding(payload.deref());
+
+        let rule = find_transform(&self.config.transforms, topic);
+        let (payload, encoding) = match rule {
+            Some(rule) => {
+                let transformed = transform_payload(payload.deref(), rule.mqtt_codec, rule.zenoh_codec)?;
+                let encoding = codec_encoding(rule.zenoh_codec, &transformed);
+                (transformed, encoding)
+            }
+            None => (payload.to_vec(), guess_encoding(payload.deref())),
+        };
         // TODO: check allow/deny
         log::trace!(
-            "MQTT client {}: route from MQTT '{}' to Zenoh '{}' (encoding={})",
+            "MQTT client {}: route from MQTT '{}' to Zenoh '{}' (encoding={}, retain={})",
             self.client_id,
             topic,
             ke,
-            encoding
+            encoding,
+            retain
         );
+
+        if retain {
+            store_retained(ke.as_str(), &payload, &encoding).await;
+        }
+
         self.zsession
-            .put(ke, payload.deref())
+            .put(ke, payload)
             .encoding(encoding)
             .allowed_destination(destination)
             .res()
@@ -136,50 +313,198 @@ impl MqttSessionState<'_> {
     }
 }
 
+/// Looks up the first transform rule whose pattern matches `topic`, comparing
+/// both as Zenoh key expressions so the MQTT-style glob in `pattern` lines up
+/// with the MQTT-style glob in `topic` (and with `allow`/`deny` matching).
+pub(crate) fn find_transform<'c>(
+    transforms: &'c [TransformRule],
+    topic: &str,
+) -> Option<&'c TransformRule> {
+    let topic_ke = mqtt_topic_to_ke(topic, &None).ok()?;
+    transforms.iter().find(|rule| {
+        mqtt_topic_to_ke(&rule.pattern, &None)
+            .map(|pattern_ke| topic_ke.intersects(&pattern_ke))
+            .unwrap_or(false)
+    })
+}
+
+// stores a retained value, or clears it when `payload` is empty - per MQTT
+// semantics a retained PUBLISH with a zero-length payload deletes the
+// retained message for the topic rather than storing an empty one
+pub(crate) async fn store_retained(ke: &str, payload: &[u8], encoding: &Encoding) {
+    if payload.is_empty() {
+        RETAINED.write().await.remove(ke);
+    } else {
+        RETAINED
+            .write()
+            .await
+            .insert(ke.to_string(), (payload.to_vec(), encoding.clone()));
+    }
+}
+
+// returns true exactly once across however many times it's called for a
+// given session, claiming the right to publish that session's Will message
+pub(crate) fn claim_will(will_published: &AtomicBool) -> bool {
+    will_published
+        .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+}
+
+pub(crate) fn codec_encoding(codec: PayloadCodec, payload: &[u8]) -> Encoding {
+    match codec {
+        PayloadCodec::CanonicalJson => Encoding::APP_JSON,
+        PayloadCodec::Raw => guess_encoding(payload),
+    }
+}
+
+/// Applies the forward (`from` -> `to`) transform between two payload
+/// representations. `Raw -> Raw` and `CanonicalJson -> CanonicalJson` are a
+/// passthrough; `Raw -> CanonicalJson` parses the payload as JSON and
+/// re-serializes it with sorted keys, and `CanonicalJson -> Raw` hands the
+/// (already canonical) bytes through unchanged.
+pub(crate) fn transform_payload(
+    payload: &[u8],
+    from: PayloadCodec,
+    to: PayloadCodec,
+) -> ZResult<Vec<u8>> {
+    match (from, to) {
+        (PayloadCodec::Raw, PayloadCodec::CanonicalJson) => {
+            let value: serde_json::Value = serde_json::from_slice(payload)
+                .map_err(|e| zerror!("payload is not valid JSON: {}", e))?;
+            serde_json::to_vec(&value).map_err(|e| zerror!("failed to re-encode JSON: {}", e).into())
+        }
+        _ => Ok(payload.to_vec()),
+    }
+}
+
+// computes the MQTT topic and (possibly transformed) payload for a Zenoh
+// sample being routed to MQTT; shared by the sync subscriber-callback path
+// and the async retained-replay path below
+fn prepare_zenoh_to_mqtt(sample: &Sample, config: &Config) -> ZResult<(ByteString, Bytes)> {
+    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, &config.scope)?;
+    let raw_payload = sample.payload.contiguous();
+    let payload = match find_transform(&config.transforms, topic.as_str()) {
+        Some(rule) => transform_payload(&raw_payload, rule.zenoh_codec, rule.mqtt_codec)?,
+        None => raw_payload.to_vec(),
+    };
+    Ok((topic, payload.into()))
+}
+
+// invoked from the (sync) Zenoh subscriber callback - the channel is
+// bounded(1) so send_blocking is appropriate here, there is no executor to stall
 fn route_zenoh_to_mqtt(
     sample: Sample,
     client_id: &str,
     config: &Config,
-    tx: &Sender<(ByteString, Bytes)>,
+    tx: &Sender<(ByteString, Bytes, QoS, bool)>,
+    qos: QoS,
+    retain: bool,
 ) -> ZResult<()> {
-    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, &config.scope)?;
+    let (topic, payload) = prepare_zenoh_to_mqtt(&sample, config)?;
     log::trace!(
-        "MQTT client {}: route from Zenoh '{}' to MQTT '{}'",
+        "MQTT client {}: route from Zenoh '{}' to MQTT '{}' (qos={:?}, retain={})",
         client_id,
         sample.key_expr,
-        topic
+        topic,
+        qos,
+        retain
     );
-    tx.send_blocking((topic, sample.payload.contiguous().to_vec().into()))
-        .map_err(|e| {
-            zerror!(
-                "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
-                client_id,
-                sample.key_expr,
-                e
-            )
-            .into()
-        })
+    tx.send_blocking((topic, payload, qos, retain)).map_err(|e| {
+        zerror!(
+            "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
+            client_id,
+            sample.key_expr,
+            e
+        )
+        .into()
+    })
+}
+
+// invoked from send_retained, which already runs on an async task - uses
+// .send().await instead of send_blocking so a full channel yields rather
+// than blocking the executor
+async fn route_zenoh_to_mqtt_async(
+    sample: Sample,
+    client_id: &str,
+    config: &Config,
+    tx: &Sender<(ByteString, Bytes, QoS, bool)>,
+    qos: QoS,
+    retain: bool,
+) -> ZResult<()> {
+    let (topic, payload) = prepare_zenoh_to_mqtt(&sample, config)?;
+    log::trace!(
+        "MQTT client {}: route from Zenoh '{}' to MQTT '{}' (qos={:?}, retain={})",
+        client_id,
+        sample.key_expr,
+        topic,
+        qos,
+        retain
+    );
+    tx.send((topic, payload, qos, retain)).await.map_err(|e| {
+        zerror!(
+            "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
+            client_id,
+            sample.key_expr,
+            e
+        )
+        .into()
+    })
 }
 
-fn spawn_mqtt_publisher(client_id: String, rx: Receiver<(ByteString, Bytes)>, sink: MqttSink) {
+fn spawn_mqtt_publisher(
+    client_id: String,
+    rx: Receiver<(ByteString, Bytes, QoS, bool)>,
+    sink: MqttSink,
+    zsession: Arc<Session>,
+    config: Arc<Config>,
+    will: Option<WillMessage>,
+    clean_disconnect: Arc<AtomicBool>,
+    will_published: Arc<AtomicBool>,
+) {
     ntex::rt::spawn(async move {
         loop {
             match rx.recv().await {
-                Ok((topic, payload)) => {
-                    if sink.is_open() {
-                        if let Err(e) = sink.publish_at_most_once(topic, payload) {
-                            log::trace!(
-                                "Failed to send MQTT message for client {} - {}",
-                                client_id,
-                                e
-                            );
-                            sink.close();
-                            break;
-                        }
-                    } else {
+                Ok((topic, payload, qos, retain)) => {
+                    if !sink.is_open() {
                         log::trace!("MQTT sink closed for client {}", client_id);
                         break;
                     }
+                    let result = match qos {
+                        QoS::AtMostOnce => {
+                            sink.publish(topic, payload).retain(retain).send_at_most_once()
+                        }
+                        _ => {
+                            // QoS 1/2: wait for the negotiated receive-maximum credit before
+                            // handing off an acked publish, so we never have more in-flight
+                            // messages than the client is willing to track; bound the ack
+                            // wait itself so a client that never acks can't wedge this
+                            // session's publisher task forever.
+                            sink.ready().await;
+                            let send = sink.publish(topic, payload).retain(retain).send();
+                            match async_std::future::timeout(ACK_TIMEOUT, send).await {
+                                Ok(Ok(_ack)) => Ok(()),
+                                Ok(Err(e)) => Err(e),
+                                Err(_) => {
+                                    log::trace!(
+                                        "MQTT client {}: timed out waiting for QoS 1/2 ack after {:?}",
+                                        client_id,
+                                        ACK_TIMEOUT
+                                    );
+                                    sink.close();
+                                    break;
+                                }
+                            }
+                        }
+                    };
+                    if let Err(e) = result {
+                        log::trace!(
+                            "Failed to send MQTT message for client {} - {}",
+                            client_id,
+                            e
+                        );
+                        sink.close();
+                        break;
+                    }
                 }
                 Err(_) => {
                     log::trace!("MPSC Channel closed for client {}", client_id);
@@ -187,5 +512,118 @@ fn spawn_mqtt_publisher(client_id: String, rx: Receiver<(ByteString, Bytes)>, si
                 }
             }
         }
+
+        // Any exit from the loop above is an ungraceful disconnect unless the
+        // DISCONNECT handling path already called `mark_clean_disconnect()`.
+        if !clean_disconnect.load(Ordering::Relaxed) {
+            if let Some(will) = &will {
+                if claim_will(&will_published) {
+                    if let Err(e) = publish_will_message(&zsession, &config, &client_id, will).await {
+                        log::warn!(
+                            "MQTT client {}: failed to publish Will message on ungraceful disconnect: {}",
+                            client_id,
+                            e
+                        );
+                    }
+                }
+            }
+        }
     });
 }
+
+pub(crate) async fn publish_will_message(
+    zsession: &Arc<Session>,
+    config: &Config,
+    client_id: &str,
+    will: &WillMessage,
+) -> ZResult<()> {
+    let destination = if is_allowed(&will.topic, config) {
+        Locality::Any
+    } else {
+        log::trace!(
+            "MQTT client {}: Will topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - publishing only to MQTT subscribers",
+            client_id,
+            will.topic
+        );
+        Locality::SessionLocal
+    };
+
+    let ke: KeyExpr = if let Some(scope) = &config.scope {
+        (scope / will.topic.as_str().try_into()?).into()
+    } else {
+        will.topic.as_str().try_into()?
+    };
+
+    let rule = find_transform(&config.transforms, &will.topic);
+    let (payload, encoding) = match rule {
+        Some(rule) => {
+            let transformed = transform_payload(will.payload.deref(), rule.mqtt_codec, rule.zenoh_codec)?;
+            let encoding = codec_encoding(rule.zenoh_codec, &transformed);
+            (transformed, encoding)
+        }
+        None => (will.payload.to_vec(), guess_encoding(will.payload.deref())),
+    };
+    log::debug!(
+        "MQTT client {}: publishing Will message on '{}' (qos={:?}, retain={})",
+        client_id,
+        ke,
+        will.qos,
+        will.retain
+    );
+
+    if will.retain {
+        store_retained(ke.as_str(), &payload, &encoding).await;
+    }
+
+    zsession
+        .put(ke, payload)
+        .encoding(encoding)
+        .allowed_destination(destination)
+        .res()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_will_only_succeeds_once() {
+        let will_published = AtomicBool::new(false);
+        assert!(claim_will(&will_published));
+        assert!(!claim_will(&will_published));
+    }
+
+    #[test]
+    fn transform_payload_canonicalizes_json() {
+        let payload = transform_payload(
+            br#"{"b": 1, "a": 2}"#,
+            PayloadCodec::Raw,
+            PayloadCodec::CanonicalJson,
+        )
+        .unwrap();
+        assert_eq!(payload, br#"{"a":2,"b":1}"#);
+    }
+
+    #[test]
+    fn transform_payload_rejects_invalid_json() {
+        assert!(transform_payload(b"not json", PayloadCodec::Raw, PayloadCodec::CanonicalJson).is_err());
+    }
+
+    #[test]
+    fn transform_payload_is_passthrough_for_matching_codecs() {
+        let payload = transform_payload(b"raw bytes", PayloadCodec::Raw, PayloadCodec::Raw).unwrap();
+        assert_eq!(payload, b"raw bytes");
+    }
+
+    #[test]
+    fn find_transform_matches_glob_pattern() {
+        let rules = vec![TransformRule {
+            pattern: "sensors/+/temperature".into(),
+            mqtt_codec: PayloadCodec::Raw,
+            zenoh_codec: PayloadCodec::CanonicalJson,
+        }];
+        assert!(find_transform(&rules, "sensors/room1/temperature").is_some());
+        assert!(find_transform(&rules, "sensors/room1/humidity").is_none());
+    }
+}
@@ -0,0 +1,870 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+// This module mirrors `mqtt_session_state` for MQTT v5 clients: the v3.1.1
+// path only ever sees a topic and a payload, while v5 PUBLISH packets also
+// carry user properties, a content-type, a response-topic and
+// correlation-data. Rather than bolt those fields onto the v3 types, we keep
+// a parallel session state here so the v3 path stays untouched and the v5
+// metadata round-trips through a dedicated Zenoh attachment.
+use crate::config::Config;
+use crate::mqtt_helpers::*;
+use crate::mqtt_session_state::{
+    claim_will, codec_encoding, find_transform, publish_will_message, transform_payload,
+    WillMessage,
+};
+use async_channel::{Receiver, Sender};
+use async_std::sync::RwLock;
+use lazy_static::__Deref;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::types::QoS;
+use ntex_mqtt::v5::codec::{PublishProperties, UserProperties, UserProperty};
+use ntex_mqtt::v5::MqttSink;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use std::{collections::HashMap, sync::Arc};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::queryable::Queryable;
+use zenoh::subscriber::Subscriber;
+
+/// How long a queryable RPC endpoint waits for the MQTT client's correlated
+/// reply before the Zenoh query is abandoned.
+const RPC_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long a QoS 1/2 publish waits for the client's ack before the session
+// is considered wedged and gets closed - mirrors mqtt_session_state.rs
+const ACK_TIMEOUT: Duration = Duration::from_secs(20);
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a correlation-data value unique within this plugin process, used
+/// to match an RPC reply PUBLISH back to the Zenoh query that requested it.
+fn next_correlation_id() -> Vec<u8> {
+    NEXT_CORRELATION_ID
+        .fetch_add(1, Ordering::Relaxed)
+        .to_be_bytes()
+        .to_vec()
+}
+
+/// The v5-only metadata carried alongside a PUBLISH, serialized into / parsed
+/// back out of a Zenoh attachment so it survives the MQTT <-> Zenoh hop.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct MqttV5Properties {
+    pub(crate) user_properties: Vec<(String, String)>,
+    pub(crate) response_topic: Option<String>,
+    pub(crate) correlation_data: Option<Vec<u8>>,
+    pub(crate) content_type: Option<String>,
+}
+
+impl MqttV5Properties {
+    fn from_publish(props: &PublishProperties) -> Self {
+        MqttV5Properties {
+            user_properties: props
+                .user_properties
+                .iter()
+                .map(|UserProperty(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            response_topic: props.response_topic.as_ref().map(|t| t.to_string()),
+            correlation_data: props.correlation_data.as_ref().map(|d| d.to_vec()),
+            content_type: props.content_type.as_ref().map(|ct| ct.to_string()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.user_properties.is_empty()
+            && self.response_topic.is_none()
+            && self.correlation_data.is_none()
+    }
+
+    /// Encodes the v5 metadata into a Zenoh attachment of key/value pairs:
+    /// the response-topic under `__response_topic`, the correlation-data
+    /// under `__correlation_data`, then one pair per user property. The
+    /// content-type is not included; it travels as the sample's encoding.
+    fn to_attachment(&self) -> Attachment {
+        let mut builder = AttachmentBuilder::new();
+        if let Some(rt) = &self.response_topic {
+            builder.insert(b"__response_topic", rt.as_bytes());
+        }
+        if let Some(cd) = &self.correlation_data {
+            builder.insert(b"__correlation_data", cd);
+        }
+        for (k, v) in &self.user_properties {
+            builder.insert(k.as_bytes(), v.as_bytes());
+        }
+        builder.build()
+    }
+
+    fn from_attachment(attachment: &Attachment) -> Self {
+        let mut props = MqttV5Properties::default();
+        for (k, v) in attachment.iter() {
+            let key = String::from_utf8_lossy(&k).into_owned();
+            let value = String::from_utf8_lossy(&v).into_owned();
+            match key.as_str() {
+                "__response_topic" => props.response_topic = Some(value),
+                "__correlation_data" => props.correlation_data = Some(v.to_vec()),
+                _ => props.user_properties.push((key, value)),
+            }
+        }
+        props
+    }
+
+    fn into_publish_properties(self) -> PublishProperties {
+        PublishProperties {
+            response_topic: self.response_topic.and_then(|t| t.try_into().ok()),
+            correlation_data: self.correlation_data.map(Bytes::from),
+            content_type: self.content_type.and_then(|ct| ct.try_into().ok()),
+            user_properties: UserProperties(
+                self.user_properties
+                    .into_iter()
+                    .filter_map(|(k, v)| Some(UserProperty(k.try_into().ok()?, v.try_into().ok()?)))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MqttSessionStateV5<'a> {
+    pub(crate) client_id: String,
+    pub(crate) zsession: Arc<Session>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) subs: RwLock<HashMap<String, Subscriber<'a, ()>>>,
+    pub(crate) queryables: RwLock<HashMap<String, Queryable<'a, ()>>>,
+    pub(crate) tx: Sender<(ByteString, Bytes, MqttV5Properties, QoS, bool)>,
+    pub(crate) will: Option<WillMessage>,
+    clean_disconnect: Arc<AtomicBool>,
+    will_published: Arc<AtomicBool>,
+    // RPC replies in flight: correlation-data -> the channel the queryable
+    // handler is blocked on, resolved when the client's reply PUBLISH comes
+    // back through `route_mqtt_to_zenoh`.
+    pending_replies: Arc<RwLock<HashMap<Vec<u8>, Sender<(Bytes, Encoding)>>>>,
+}
+
+impl MqttSessionStateV5<'_> {
+    pub(crate) fn new<'a>(
+        client_id: String,
+        zsession: Arc<Session>,
+        config: Arc<Config>,
+        sink: MqttSink,
+        will: Option<WillMessage>,
+    ) -> MqttSessionStateV5<'a> {
+        let (tx, rx) =
+            async_channel::bounded::<(ByteString, Bytes, MqttV5Properties, QoS, bool)>(1);
+        let clean_disconnect = Arc::new(AtomicBool::new(false));
+        let will_published = Arc::new(AtomicBool::new(false));
+        spawn_mqtt_publisher_v5(
+            client_id.clone(),
+            rx,
+            sink,
+            zsession.clone(),
+            config.clone(),
+            will.clone(),
+            clean_disconnect.clone(),
+            will_published.clone(),
+        );
+
+        MqttSessionStateV5 {
+            client_id,
+            zsession,
+            config,
+            subs: RwLock::new(HashMap::new()),
+            queryables: RwLock::new(HashMap::new()),
+            tx,
+            will,
+            clean_disconnect,
+            will_published,
+            pending_replies: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // marks this session as having ended with a clean DISCONNECT, so the
+    // teardown path does not publish the client's Will message
+    pub(crate) fn mark_clean_disconnect(&self) {
+        self.clean_disconnect.store(true, Ordering::Relaxed);
+    }
+
+    // publishes this session's Will message to Zenoh, if any and if it
+    // hasn't been published already - shares a claim flag with the
+    // automatic teardown path in spawn_mqtt_publisher_v5, mirroring
+    // mqtt_session_state.rs::publish_will
+    pub(crate) async fn publish_will(&self) -> ZResult<()> {
+        match &self.will {
+            Some(will) if claim_will(&self.will_published) => {
+                publish_will_message(&self.zsession, &self.config, &self.client_id, will).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) async fn map_mqtt_subscription<'a>(&'a self, topic: &str, qos: QoS) -> ZResult<()> {
+        if is_rpc_topic(topic, &self.config) {
+            return self.map_rpc_topic(topic).await;
+        }
+
+        let sub_origin = if is_allowed(topic, &self.config) {
+            Locality::Any
+        } else {
+            log::debug!(
+                "MQTT Client {}: topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - re-publish only from MQTT publishers",
+                self.client_id,
+                topic
+            );
+            Locality::SessionLocal
+        };
+
+        let mut subs = self.subs.write().await;
+        if !subs.contains_key(topic) {
+            let ke = mqtt_topic_to_ke(topic, &self.config.scope)?;
+            let client_id = self.client_id.clone();
+            let config = self.config.clone();
+            let tx = self.tx.clone();
+            let sub = self
+                .zsession
+                .declare_subscriber(ke.clone())
+                .callback(move |sample| {
+                    if let Err(e) = route_zenoh_to_mqtt_v5(sample, &client_id, &config, &tx, qos, false)
+                    {
+                        log::warn!("{}", e);
+                    }
+                })
+                .allowed_origin(sub_origin)
+                .res()
+                .await?;
+            subs.insert(topic.into(), sub);
+            self.send_retained(&ke, topic, qos).await;
+            Ok(())
+        } else {
+            log::debug!(
+                "MQTT Client {} already subscribes to {} => ignore",
+                self.client_id,
+                topic
+            );
+            Ok(())
+        }
+    }
+
+    // serves any retained message matching `ke` to a client that just subscribed
+    // to `topic` - same query-first, local-fallback strategy as
+    // mqtt_session_state.rs::send_retained, sharing its RETAINED store so a
+    // v3 client's retained publication is visible to a v5 subscriber and
+    // vice versa
+    async fn send_retained(&self, ke: &KeyExpr<'_>, topic: &str, qos: QoS) {
+        let mut served_by_query = false;
+
+        match self.zsession.get(ke).res().await {
+            Ok(replies) => {
+                while let Ok(reply) = replies.recv_async().await {
+                    match reply.sample {
+                        Ok(sample) => {
+                            served_by_query = true;
+                            if let Err(e) = route_zenoh_to_mqtt_v5_async(
+                                sample,
+                                &self.client_id,
+                                &self.config,
+                                &self.tx,
+                                qos,
+                                true,
+                            )
+                            .await
+                            {
+                                log::warn!("{}", e);
+                            }
+                        }
+                        Err(e) => log::debug!(
+                            "MQTT client {}: error fetching retained value for '{}': {}",
+                            self.client_id,
+                            ke,
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) => log::debug!(
+                "MQTT client {}: failed to query retained value for '{}': {}",
+                self.client_id,
+                ke,
+                e
+            ),
+        }
+
+        if served_by_query {
+            return;
+        }
+
+        if let Some((payload, encoding)) = crate::mqtt_session_state::RETAINED.read().await.get(ke.as_str()) {
+            log::trace!(
+                "MQTT client {}: serving locally-retained value for '{}' to newly subscribed '{}'",
+                self.client_id,
+                ke,
+                topic
+            );
+            let props = MqttV5Properties {
+                content_type: Some(encoding.to_string()),
+                ..Default::default()
+            };
+            if let Err(e) = self
+                .tx
+                .send((topic.into(), payload.clone().into(), props, qos, true))
+                .await
+            {
+                log::warn!(
+                    "MQTT client {}: error re-publishing retained value for '{}': {}",
+                    self.client_id,
+                    topic,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Declares `topic` as a Zenoh queryable instead of a plain subscriber:
+    /// each incoming query is forwarded to this MQTT client as a PUBLISH
+    /// carrying a dedicated response-topic and correlation-data, and the
+    /// client's correlated reply (caught in `route_mqtt_to_zenoh`) is
+    /// returned as the query's reply.
+    pub(crate) async fn map_rpc_topic<'a>(&'a self, topic: &str) -> ZResult<()> {
+        let mut queryables = self.queryables.write().await;
+        if queryables.contains_key(topic) {
+            return Ok(());
+        }
+
+        let origin = if is_allowed(topic, &self.config) {
+            Locality::Any
+        } else {
+            log::debug!(
+                "MQTT Client {}: RPC topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - serving queryable to this plugin only",
+                self.client_id,
+                topic
+            );
+            Locality::SessionLocal
+        };
+
+        let ke = mqtt_topic_to_ke(topic, &self.config.scope)?;
+        let request_topic: ByteString = topic.into();
+        let reply_topic = format!("{}/_reply", topic);
+        let client_id = self.client_id.clone();
+        let tx = self.tx.clone();
+        let pending_replies = self.pending_replies.clone();
+
+        let qable = self
+            .zsession
+            .declare_queryable(ke)
+            .allowed_origin(origin)
+            .callback(move |query| {
+                let client_id = client_id.clone();
+                let tx = tx.clone();
+                let pending_replies = pending_replies.clone();
+                let request_topic = request_topic.clone();
+                let reply_topic = reply_topic.clone();
+                ntex::rt::spawn(async move {
+                    let query_ke = query.key_expr().clone();
+                    let payload: Bytes = query
+                        .value()
+                        .map(|v| v.payload.contiguous().to_vec().into())
+                        .unwrap_or_default();
+                    let content_type = query.value().map(|v| v.encoding.to_string());
+                    let corr_id = next_correlation_id();
+                    let (reply_tx, reply_rx) = async_channel::bounded::<(Bytes, Encoding)>(1);
+                    pending_replies
+                        .write()
+                        .await
+                        .insert(corr_id.clone(), reply_tx);
+
+                    let props = MqttV5Properties {
+                        user_properties: Vec::new(),
+                        response_topic: Some(reply_topic.clone()),
+                        correlation_data: Some(corr_id.clone()),
+                        content_type,
+                    };
+                    if tx
+                        .send((request_topic, payload, props, QoS::AtMostOnce, false))
+                        .await
+                        .is_err()
+                    {
+                        log::warn!(
+                            "MQTT client {}: RPC request channel closed, failing query on '{}'",
+                            client_id,
+                            query_ke
+                        );
+                        pending_replies.write().await.remove(&corr_id);
+                        return;
+                    }
+
+                    match async_std::future::timeout(RPC_REPLY_TIMEOUT, reply_rx.recv()).await {
+                        Ok(Ok((payload, encoding))) => {
+                            let sample = Sample::new(query_ke.clone(), payload).with_encoding(encoding);
+                            if let Err(e) = query.reply(Ok(sample)).res().await {
+                                log::warn!(
+                                    "MQTT client {}: failed to reply to RPC query on '{}': {}",
+                                    client_id,
+                                    query_ke,
+                                    e
+                                );
+                            }
+                        }
+                        _ => {
+                            log::debug!(
+                                "MQTT client {}: RPC reply on '{}' timed out after {:?}",
+                                client_id,
+                                reply_topic,
+                                RPC_REPLY_TIMEOUT
+                            );
+                            pending_replies.write().await.remove(&corr_id);
+                        }
+                    }
+                });
+            })
+            .res()
+            .await?;
+        queryables.insert(topic.into(), qable);
+        Ok(())
+    }
+
+    pub(crate) async fn route_mqtt_to_zenoh(
+        &self,
+        mqtt_topic: &ntex::router::Path<ByteString>,
+        payload: &Bytes,
+        props: &PublishProperties,
+        retain: bool,
+    ) -> ZResult<()> {
+        let topic = mqtt_topic.get_ref().as_str();
+
+        // a v5 content-type, when present, takes priority over the sniffed encoding
+        let encoding = match &props.content_type {
+            Some(ct) => Encoding::from(ct.to_string()),
+            None => guess_encoding(payload.deref()),
+        };
+
+        // this PUBLISH is the client's correlated reply to an earlier RPC
+        // query served by `map_rpc_topic` - resolve it and stop, it is not a
+        // regular message to route onward.
+        if let Some(corr_id) = &props.correlation_data {
+            if let Some(reply_tx) = self.pending_replies.write().await.remove(corr_id.as_ref()) {
+                log::trace!(
+                    "MQTT client {}: resolving RPC reply on '{}' (correlation_data matched)",
+                    self.client_id,
+                    topic
+                );
+                let _ = reply_tx.send((payload.clone(), encoding)).await;
+                return Ok(());
+            }
+        }
+
+        // this PUBLISH carries a response-topic of its own: if `topic` is
+        // configured as an RPC topic, the client is issuing an RPC request -
+        // query Zenoh once and deliver the first reply back on the client's
+        // response-topic, instead of a plain put. A response-topic set for
+        // some other, unrelated application purpose must not be hijacked.
+        if is_rpc_topic(topic, &self.config) {
+            if let Some(response_topic) = &props.response_topic {
+                return self
+                    .route_rpc_request(
+                        topic,
+                        payload,
+                        encoding,
+                        response_topic.to_string(),
+                        &props.correlation_data,
+                    )
+                    .await;
+            }
+        }
+
+        let destination = if is_allowed(topic, &self.config) {
+            Locality::Any
+        } else {
+            log::trace!(
+                "MQTT Client {}: topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - re-publish only to MQTT subscriber",
+                self.client_id,
+                topic
+            );
+            Locality::SessionLocal
+        };
+
+        let ke: KeyExpr = if let Some(scope) = &self.config.scope {
+            (scope / topic.try_into()?).into()
+        } else {
+            topic.try_into()?
+        };
+
+        let rule = find_transform(&self.config.transforms, topic);
+        let (payload, encoding) = match rule {
+            Some(rule) => {
+                let transformed = transform_payload(payload.deref(), rule.mqtt_codec, rule.zenoh_codec)?;
+                // a v5 content-type, when present, still takes priority over the codec's encoding
+                let encoding = match &props.content_type {
+                    Some(ct) => Encoding::from(ct.to_string()),
+                    None => codec_encoding(rule.zenoh_codec, &transformed),
+                };
+                (transformed, encoding)
+            }
+            None => (payload.to_vec(), encoding),
+        };
+
+        let v5_props = MqttV5Properties::from_publish(props);
+        log::trace!(
+            "MQTT client {}: route from MQTT '{}' to Zenoh '{}' (encoding={}, retain={}, user_properties={})",
+            self.client_id,
+            topic,
+            ke,
+            encoding,
+            retain,
+            v5_props.user_properties.len()
+        );
+
+        if retain {
+            crate::mqtt_session_state::store_retained(ke.as_str(), &payload, &encoding).await;
+        }
+
+        let put = self
+            .zsession
+            .put(ke, payload)
+            .encoding(encoding)
+            .allowed_destination(destination);
+        let put = if v5_props.is_empty() {
+            put
+        } else {
+            put.with_attachment(v5_props.to_attachment())
+        };
+        put.res().await
+    }
+
+    /// Serves a PUBLISH that carries its own response-topic as an RPC
+    /// request: queries Zenoh on the mapped keyexpr and, once the first
+    /// reply comes in, publishes it back to this MQTT client on
+    /// `response_topic` with the original correlation-data echoed back.
+    async fn route_rpc_request(
+        &self,
+        topic: &str,
+        payload: &Bytes,
+        encoding: Encoding,
+        response_topic: String,
+        correlation_data: &Option<Bytes>,
+    ) -> ZResult<()> {
+        let destination = if is_allowed(topic, &self.config) {
+            Locality::Any
+        } else {
+            log::trace!(
+                "MQTT Client {}: RPC topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - querying this plugin only",
+                self.client_id,
+                topic
+            );
+            Locality::SessionLocal
+        };
+
+        let ke: KeyExpr = if let Some(scope) = &self.config.scope {
+            (scope / topic.try_into()?).into()
+        } else {
+            topic.try_into()?
+        };
+        log::trace!(
+            "MQTT client {}: route RPC request from MQTT '{}' to Zenoh query '{}'",
+            self.client_id,
+            topic,
+            ke
+        );
+
+        let replies = self
+            .zsession
+            .get(&ke)
+            .value(payload.deref())
+            .encoding(encoding)
+            .allowed_destination(destination)
+            .res()
+            .await?;
+        let reply = match async_std::future::timeout(RPC_REPLY_TIMEOUT, replies.recv_async()).await
+        {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => {
+                return Err(zerror!(
+                    "MQTT client {}: no reply received for RPC query '{}': {}",
+                    self.client_id,
+                    ke,
+                    e
+                )
+                .into())
+            }
+            Err(_) => {
+                return Err(zerror!(
+                    "MQTT client {}: RPC query '{}' timed out after {:?}",
+                    self.client_id,
+                    ke,
+                    RPC_REPLY_TIMEOUT
+                )
+                .into())
+            }
+        };
+        let sample = reply.sample.map_err(|e| {
+            zerror!(
+                "MQTT client {}: RPC query '{}' returned an error: {}",
+                self.client_id,
+                ke,
+                e
+            )
+        })?;
+
+        let props = MqttV5Properties {
+            user_properties: Vec::new(),
+            response_topic: None,
+            correlation_data: correlation_data.as_ref().map(|d| d.to_vec()),
+            content_type: Some(sample.encoding.to_string()),
+        };
+        self.tx
+            .send((
+                response_topic.into(),
+                sample.payload.contiguous().to_vec().into(),
+                props,
+                QoS::AtMostOnce,
+                false,
+            ))
+            .await
+            .map_err(|e| {
+                zerror!(
+                    "MQTT client {}: error delivering RPC reply to client: {}",
+                    self.client_id,
+                    e
+                )
+                .into()
+            })
+    }
+}
+
+/// Whether `topic` is configured to be served as a Zenoh queryable (RPC mode)
+/// rather than as a plain subscription; matched against `Config::rpc_topics`
+/// the same way `allow`/`deny` patterns are matched.
+fn is_rpc_topic(topic: &str, config: &Config) -> bool {
+    config
+        .rpc_topics
+        .iter()
+        .any(|pattern| match (mqtt_topic_to_ke(topic, &None), mqtt_topic_to_ke(pattern, &None)) {
+            (Ok(topic_ke), Ok(pattern_ke)) => topic_ke.intersects(&pattern_ke),
+            _ => false,
+        })
+}
+
+// computes the MQTT topic and v5 properties for a Zenoh sample being routed
+// to MQTT; shared by the sync subscriber-callback path and the async
+// retained-replay path below
+fn prepare_zenoh_to_mqtt_v5(sample: &Sample, config: &Config) -> ZResult<(ByteString, Bytes, MqttV5Properties)> {
+    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, &config.scope)?;
+    let mut props = sample
+        .attachment()
+        .map(MqttV5Properties::from_attachment)
+        .unwrap_or_default();
+    // mirror the MQTT->Zenoh leg, which folds a v5 content-type into the
+    // Zenoh encoding (see route_mqtt_to_zenoh) - read it back so content-type
+    // round-trips on the way back to MQTT
+    props.content_type = Some(sample.encoding.to_string());
+
+    let raw_payload = sample.payload.contiguous();
+    let payload = match find_transform(&config.transforms, topic.as_str()) {
+        Some(rule) => transform_payload(&raw_payload, rule.zenoh_codec, rule.mqtt_codec)?,
+        None => raw_payload.to_vec(),
+    };
+    Ok((topic, payload.into(), props))
+}
+
+// invoked from the (sync) Zenoh subscriber callback - the channel is
+// bounded(1) so send_blocking is appropriate here, there is no executor to stall
+fn route_zenoh_to_mqtt_v5(
+    sample: Sample,
+    client_id: &str,
+    config: &Config,
+    tx: &Sender<(ByteString, Bytes, MqttV5Properties, QoS, bool)>,
+    qos: QoS,
+    retain: bool,
+) -> ZResult<()> {
+    let key_expr = sample.key_expr.clone();
+    let (topic, payload, props) = prepare_zenoh_to_mqtt_v5(&sample, config)?;
+    log::trace!(
+        "MQTT client {}: route from Zenoh '{}' to MQTT '{}' (qos={:?}, retain={}, user_properties={})",
+        client_id,
+        key_expr,
+        topic,
+        qos,
+        retain,
+        props.user_properties.len()
+    );
+    tx.send_blocking((topic, payload, props, qos, retain)).map_err(|e| {
+        zerror!(
+            "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
+            client_id,
+            key_expr,
+            e
+        )
+        .into()
+    })
+}
+
+// invoked from send_retained, which already runs on an async task - uses
+// .send().await instead of send_blocking so a full channel yields rather
+// than blocking the executor
+async fn route_zenoh_to_mqtt_v5_async(
+    sample: Sample,
+    client_id: &str,
+    config: &Config,
+    tx: &Sender<(ByteString, Bytes, MqttV5Properties, QoS, bool)>,
+    qos: QoS,
+    retain: bool,
+) -> ZResult<()> {
+    let key_expr = sample.key_expr.clone();
+    let (topic, payload, props) = prepare_zenoh_to_mqtt_v5(&sample, config)?;
+    log::trace!(
+        "MQTT client {}: route from Zenoh '{}' to MQTT '{}' (qos={:?}, retain={}, user_properties={})",
+        client_id,
+        key_expr,
+        topic,
+        qos,
+        retain,
+        props.user_properties.len()
+    );
+    tx.send((topic, payload, props, qos, retain)).await.map_err(|e| {
+        zerror!(
+            "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
+            client_id,
+            key_expr,
+            e
+        )
+        .into()
+    })
+}
+
+fn spawn_mqtt_publisher_v5(
+    client_id: String,
+    rx: Receiver<(ByteString, Bytes, MqttV5Properties, QoS, bool)>,
+    sink: MqttSink,
+    zsession: Arc<Session>,
+    config: Arc<Config>,
+    will: Option<WillMessage>,
+    clean_disconnect: Arc<AtomicBool>,
+    will_published: Arc<AtomicBool>,
+) {
+    ntex::rt::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok((topic, payload, props, qos, retain)) => {
+                    if !sink.is_open() {
+                        log::trace!("MQTT sink closed for client {}", client_id);
+                        break;
+                    }
+                    if matches!(qos, QoS::AtLeastOnce | QoS::ExactlyOnce) {
+                        // QoS 1/2: mirrors mqtt_session_state.rs - wait for the
+                        // negotiated receive-maximum credit before handing off an
+                        // acked publish.
+                        sink.ready().await;
+                    }
+                    let publish = sink
+                        .publish(topic, payload)
+                        .retain(retain)
+                        .properties(move |p| *p = props.clone().into_publish_properties());
+                    let result = match qos {
+                        QoS::AtMostOnce => publish.send_at_most_once(),
+                        _ => {
+                            // bound the ack wait itself so a client that never acks
+                            // can't wedge this session's publisher task forever.
+                            match async_std::future::timeout(ACK_TIMEOUT, publish.send()).await {
+                                Ok(Ok(_ack)) => Ok(()),
+                                Ok(Err(e)) => Err(e),
+                                Err(_) => {
+                                    log::trace!(
+                                        "MQTT client {}: timed out waiting for QoS 1/2 ack after {:?}",
+                                        client_id,
+                                        ACK_TIMEOUT
+                                    );
+                                    sink.close();
+                                    break;
+                                }
+                            }
+                        }
+                    };
+                    if let Err(e) = result {
+                        log::trace!(
+                            "Failed to send MQTT message for client {} - {}",
+                            client_id,
+                            e
+                        );
+                        sink.close();
+                        break;
+                    }
+                }
+                Err(_) => {
+                    log::trace!("MPSC Channel closed for client {}", client_id);
+                    break;
+                }
+            }
+        }
+
+        // Any exit from the loop above is an ungraceful disconnect unless the
+        // DISCONNECT handling path already called `mark_clean_disconnect()`.
+        if !clean_disconnect.load(Ordering::Relaxed) {
+            if let Some(will) = &will {
+                if claim_will(&will_published) {
+                    if let Err(e) = publish_will_message(&zsession, &config, &client_id, will).await {
+                        log::warn!(
+                            "MQTT client {}: failed to publish Will message on ungraceful disconnect: {}",
+                            client_id,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_rpc_topic_matches_glob_pattern() {
+        let config = Config {
+            rpc_topics: vec!["rpc/+/request".into()],
+            ..Default::default()
+        };
+        assert!(is_rpc_topic("rpc/add/request", &config));
+        assert!(!is_rpc_topic("rpc/add/response", &config));
+    }
+
+    #[test]
+    fn next_correlation_id_is_unique_per_call() {
+        let first = next_correlation_id();
+        let second = next_correlation_id();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn mqtt_v5_properties_attachment_round_trips_user_properties_and_correlation_data() {
+        let props = MqttV5Properties {
+            user_properties: vec![("k1".into(), "v1".into())],
+            response_topic: Some("reply/topic".into()),
+            correlation_data: Some(vec![1, 2, 3]),
+            content_type: None,
+        };
+        let roundtripped = MqttV5Properties::from_attachment(&props.to_attachment());
+        assert_eq!(roundtripped.user_properties, props.user_properties);
+        assert_eq!(roundtripped.response_topic, props.response_topic);
+        assert_eq!(roundtripped.correlation_data, props.correlation_data);
+    }
+
+    #[test]
+    fn mqtt_v5_properties_is_empty_ignores_content_type() {
+        let props = MqttV5Properties {
+            content_type: Some("application/json".into()),
+            ..Default::default()
+        };
+        assert!(props.is_empty());
+    }
+}